@@ -1,12 +1,33 @@
-use crate::{Clircle, Stdio};
+use crate::{Clircle, FileType, Stdio};
 
 use std::convert::TryFrom;
-use std::fs::File;
-use std::io::{self, Seek};
-use std::os::fd::AsRawFd;
-use std::os::unix::fs::MetadataExt;
-use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
-use std::{cmp, hash, ops};
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::mem::ManuallyDrop;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt};
+use std::{cmp, hash};
+
+/// The file descriptor backing a `UnixIdentifier`.
+///
+/// An owning identifier closes the descriptor on drop, while a borrowing one (for example one of
+/// the stdio streams) leaves it untouched.
+#[derive(Debug)]
+enum IdentifierFd {
+    /// The identifier owns the descriptor and will close it when dropped.
+    Owned(OwnedFd),
+    /// The identifier only borrows the descriptor and must never close it.
+    Borrowed(BorrowedFd<'static>),
+}
+
+impl IdentifierFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            IdentifierFd::Owned(fd) => fd.as_fd(),
+            IdentifierFd::Borrowed(fd) => fd.as_fd(),
+        }
+    }
+}
 
 /// Implementation of `Clircle` for Unix.
 #[derive(Debug)]
@@ -14,24 +35,124 @@ pub struct UnixIdentifier {
     device: u64,
     inode: u64,
     size: u64,
-    is_regular_file: bool,
-    file: Option<File>,
-    owns_fd: bool,
+    file_type: FileType,
+    fd: IdentifierFd,
 }
 
 impl UnixIdentifier {
-    fn file(&self) -> &File {
-        self.file.as_ref().expect("Called file() on an identifier that has already been destroyed, this should never happen! Please file a bug!")
+    fn from_metadata(metadata: &fs::Metadata, fd: IdentifierFd) -> Self {
+        UnixIdentifier {
+            device: metadata.dev(),
+            inode: metadata.ino(),
+            size: metadata.size(),
+            file_type: file_type_of(&metadata.file_type()),
+            fd,
+        }
+    }
+
+    /// Wraps the borrowed descriptor in a `File` that will never be closed, so that it can be
+    /// queried without surrendering ownership.
+    fn borrowed_file(&self) -> ManuallyDrop<File> {
+        // Safety: The `File` is wrapped in `ManuallyDrop`, so the descriptor is never closed
+        // here; the actual ownership lives in `self.fd`.
+        ManuallyDrop::new(unsafe { File::from_raw_fd(self.fd.as_fd().as_raw_fd()) })
     }
 
     fn current_file_offset(&self) -> io::Result<u64> {
-        self.file().stream_position()
+        let mut file = self.borrowed_file();
+        file.stream_position()
     }
 
     fn has_content_left_to_read(&self) -> io::Result<bool> {
         Ok(self.current_file_offset()? < self.size)
     }
 
+    /// Copies the part of the input that has not been read yet into an anonymous file and returns
+    /// it.
+    ///
+    /// This is meant as a recovery path for a conflict detected by
+    /// [`surely_conflicts_with`](Clircle::surely_conflicts_with): the classic `cat < x > x` can be
+    /// salvaged by snapshotting the still-unread tail (from `current_file_offset` up to the
+    /// `size` captured at construction) before the original file is truncated or overwritten. The
+    /// returned file is rewound to offset `0`, so it can be read from the start, and is backed by
+    /// `memfd_create(2)` (with `MFD_CLOEXEC`) where available, falling back to an unlinked
+    /// temporary file otherwise. Already-consumed bytes are never duplicated, and an input with
+    /// nothing left to read yields an empty but valid file.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the anonymous file cannot be created or if reading from the input or writing to
+    /// the snapshot fails.
+    pub fn snapshot_remaining(&self) -> io::Result<File> {
+        let start = self.current_file_offset()?;
+        let remaining = self.size.saturating_sub(start);
+
+        let mut snapshot = Self::anonymous_file()?;
+        let source = self.borrowed_file();
+        let mut offset = start;
+        let mut left = remaining;
+        let mut buf = [0_u8; 64 * 1024];
+        while left > 0 {
+            let want = cmp::min(left, buf.len() as u64) as usize;
+            // `read_at` leaves the original file offset untouched, so a concurrent reader is not
+            // disturbed by taking the snapshot.
+            let read = source.read_at(&mut buf[..want], offset)?;
+            if read == 0 {
+                break;
+            }
+            snapshot.write_all(&buf[..read])?;
+            offset += read as u64;
+            left -= read as u64;
+        }
+
+        snapshot.seek(SeekFrom::Start(0))?;
+        Ok(snapshot)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn anonymous_file() -> io::Result<File> {
+        // Safety: The name is a valid NUL-terminated C string and the flags are valid.
+        let fd = unsafe {
+            libc::memfd_create(b"clircle-snapshot\0".as_ptr().cast(), libc::MFD_CLOEXEC)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safety: `memfd_create` returned a fresh owned descriptor.
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn anonymous_file() -> io::Result<File> {
+        // No `memfd_create` here, so create a uniquely named file in the temporary directory and
+        // unlink it immediately: the descriptor stays valid and the file vanishes from the
+        // filesystem, giving the same anonymous, auto-cleaned semantics as a memfd.
+        use std::sync::atomic::{AtomicU32, Ordering};
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+        let path = format!(
+            "{}/clircle-snapshot.{}.{}\0",
+            std::env::temp_dir().display(),
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        // Safety: `path` is a valid NUL-terminated C string.
+        let fd = unsafe {
+            libc::open(
+                path.as_ptr().cast(),
+                libc::O_RDWR | libc::O_CREAT | libc::O_EXCL | libc::O_CLOEXEC,
+                0o600,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safety: `path` is a valid NUL-terminated C string; unlinking leaves the open descriptor
+        // usable while removing the name from the filesystem.
+        unsafe { libc::unlink(path.as_ptr().cast()) };
+        // Safety: `open` returned a fresh owned descriptor.
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
     /// Creates a `UnixIdentifier` from a raw file descriptor. The preferred way to create a
     /// `UnixIdentifier` is through one of the `TryFrom` implementations.
     ///
@@ -39,40 +160,78 @@ impl UnixIdentifier {
     ///
     /// The `owns_fd` argument should only be true, if the given file descriptor owns the resource
     /// it points to (for example a file).
-    /// If it is true, a `File` can be obtained back with `Clircle::into_inner`, or it will be
-    /// closed when the `UnixIdentifier` is dropped.
+    /// If it is true, a `File` can be obtained back with `Clircle::into_inner`, or
+    /// the descriptor will be closed when the `UnixIdentifier` is dropped.
+    /// If it is false, the descriptor must stay valid for at least as long as the returned
+    /// identifier.
     ///
     /// # Errors
     ///
     /// The underlying call to `File::metadata` fails.
     pub unsafe fn try_from_raw_fd(fd: RawFd, owns_fd: bool) -> io::Result<Self> {
-        Self::try_from(File::from_raw_fd(fd)).map(|mut ident| {
-            ident.owns_fd = owns_fd;
-            ident
-        })
+        if owns_fd {
+            Self::try_from(File::from_raw_fd(fd))
+        } else {
+            Self::from_borrowed_fd(BorrowedFd::borrow_raw(fd))
+        }
+    }
+
+    /// Builds a borrowing identifier from a descriptor that is not owned.
+    ///
+    /// # Safety
+    ///
+    /// The identifier re-borrows the descriptor for the `'static` lifetime, so the caller must
+    /// ensure that `fd` stays valid for at least as long as the returned identifier. This is why
+    /// there is no safe `TryFrom<BorrowedFd>`: a safe constructor could not impose that obligation,
+    /// letting safe code drop the owner and leave the identifier pointing at a closed descriptor.
+    unsafe fn from_borrowed_fd(fd: BorrowedFd<'_>) -> io::Result<Self> {
+        // Read the metadata through a non-owning `File`, so that the `device`/`inode`/`size`
+        // snapshot is captured even though we never take ownership of the descriptor.
+        let file = ManuallyDrop::new(File::from_raw_fd(fd.as_raw_fd()));
+        let metadata = file.metadata()?;
+        let borrowed = BorrowedFd::borrow_raw(fd.as_raw_fd());
+        Ok(Self::from_metadata(&metadata, IdentifierFd::Borrowed(borrowed)))
     }
 }
 
 impl Clircle for UnixIdentifier {
     #[must_use]
-    fn into_inner(mut self) -> Option<File> {
-        if self.owns_fd {
-            self.owns_fd = false;
-            self.file.take()
-        } else {
-            None
+    fn into_inner(self) -> Option<File> {
+        match self.fd {
+            IdentifierFd::Owned(fd) => Some(File::from(fd)),
+            IdentifierFd::Borrowed(_) => None,
         }
     }
 
+    fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
     /// This method implements the conflict check that is used in the GNU coreutils program `cat`.
     #[must_use]
     fn surely_conflicts_with(&self, other: &Self) -> bool {
         PartialEq::eq(self, other)
-            && self.is_regular_file
+            && self.file_type == FileType::File
             && other.has_content_left_to_read().unwrap_or(true)
     }
 }
 
+/// Maps a standard library file type to the crate's [`FileType`], recognizing the non-regular
+/// kinds (FIFOs, character devices and sockets) that callers legitimately feed through a pipeline.
+fn file_type_of(file_type: &fs::FileType) -> FileType {
+    if file_type.is_file() {
+        FileType::File
+    } else if file_type.is_fifo() {
+        FileType::Pipe
+    } else if file_type.is_char_device() {
+        FileType::Char
+    } else if file_type.is_socket() {
+        FileType::Socket
+    } else {
+        FileType::Other
+    }
+}
+
 impl TryFrom<Stdio> for UnixIdentifier {
     type Error = <Self as TryFrom<File>>::Error;
 
@@ -82,17 +241,9 @@ impl TryFrom<Stdio> for UnixIdentifier {
             Stdio::Stdout => io::stdout().as_raw_fd(),
             Stdio::Stderr => io::stderr().as_raw_fd(),
         };
-        // Safety: It is okay to create the file, because it won't be dropped later since the
-        // `owns_fd` field is not set.
-        unsafe { Self::try_from_raw_fd(fd, false) }
-    }
-}
-
-impl ops::Drop for UnixIdentifier {
-    fn drop(&mut self) {
-        if !self.owns_fd {
-            let _ = self.file.take().map(IntoRawFd::into_raw_fd);
-        }
+        // Safety: The standard streams are valid for the entire lifetime of the program, so
+        // borrowing them for the lifetime of the identifier is sound.
+        unsafe { Self::from_borrowed_fd(BorrowedFd::borrow_raw(fd)) }
     }
 }
 
@@ -100,14 +251,11 @@ impl TryFrom<File> for UnixIdentifier {
     type Error = io::Error;
 
     fn try_from(file: File) -> Result<Self, Self::Error> {
-        file.metadata().map(|metadata| Self {
-            device: metadata.dev(),
-            inode: metadata.ino(),
-            size: metadata.size(),
-            is_regular_file: metadata.file_type().is_file(),
-            file: Some(file),
-            owns_fd: true,
-        })
+        let metadata = file.metadata()?;
+        Ok(Self::from_metadata(
+            &metadata,
+            IdentifierFd::Owned(OwnedFd::from(file)),
+        ))
     }
 }
 
@@ -133,6 +281,7 @@ mod tests {
 
     use std::error::Error;
     use std::io::Write;
+    use std::os::fd::IntoRawFd;
 
     use nix::pty::{openpty, OpenptyResult};
     use nix::unistd::close;
@@ -1,9 +1,10 @@
-use crate::{Clircle, Stdio};
+use crate::{Clircle, FileType, Stdio};
 
 use windows::Win32::{
-    Foundation::{CloseHandle, HANDLE, INVALID_HANDLE_VALUE},
+    Foundation::HANDLE,
     Storage::FileSystem::{
-        GetFileInformationByHandle, GetFileType, BY_HANDLE_FILE_INFORMATION, FILE_TYPE_DISK,
+        GetFileInformationByHandle, GetFileType, SetFilePointerEx, BY_HANDLE_FILE_INFORMATION,
+        FILE_CURRENT, FILE_TYPE_CHAR, FILE_TYPE_DISK, FILE_TYPE_PIPE,
     },
     System::Console::{GetStdHandle, STD_ERROR_HANDLE, STD_INPUT_HANDLE, STD_OUTPUT_HANDLE},
 };
@@ -11,64 +12,126 @@ use windows::Win32::{
 use std::convert::TryFrom;
 use std::fs::File;
 use std::mem::MaybeUninit;
-use std::os::windows::io::{FromRawHandle, IntoRawHandle};
-use std::{cmp, hash, io, mem, ops};
+use std::os::windows::io::{AsHandle, AsRawHandle, BorrowedHandle, OwnedHandle};
+use std::{cmp, hash, io};
+
+/// The handle backing an `Identifier`.
+///
+/// An owning identifier closes the handle on drop, while a borrowing one (for example one of the
+/// stdio streams) leaves it untouched.
+#[derive(Debug)]
+enum IdentifierHandle {
+    /// The identifier owns the handle and will close it when dropped.
+    Owned(OwnedHandle),
+    /// The identifier only borrows the handle and must never close it.
+    Borrowed(BorrowedHandle<'static>),
+}
+
+impl IdentifierHandle {
+    fn as_handle(&self) -> BorrowedHandle<'_> {
+        match self {
+            IdentifierHandle::Owned(handle) => handle.as_handle(),
+            IdentifierHandle::Borrowed(handle) => handle.as_handle(),
+        }
+    }
+
+    /// The raw Win32 `HANDLE` for use with the file system APIs.
+    fn raw(&self) -> HANDLE {
+        HANDLE(self.as_handle().as_raw_handle() as _)
+    }
+}
 
 /// Implementation of `Clircle` for Windows.
 #[derive(Debug)]
 pub(crate) struct Identifier {
     volume_serial: u32,
     file_index: u64,
-    handle: HANDLE,
-    owns_handle: bool,
+    size: u64,
+    file_type: FileType,
+    handle: IdentifierHandle,
 }
 
 impl Identifier {
-    unsafe fn try_from_raw_handle(handle: HANDLE, owns_handle: bool) -> Result<Self, io::Error> {
-        if handle.is_invalid() {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Tried to convert handle to Identifier that was invalid or null.",
-            ));
-        }
+    fn current_file_offset(&self) -> io::Result<u64> {
+        let mut position = 0_i64;
+        // SAFETY: The handle is valid and moving by zero bytes from the current position only
+        // reports the offset without changing it.
+        // https://docs.microsoft.com/en-us/windows/win32/api/fileapi/nf-fileapi-setfilepointerex
+        unsafe { SetFilePointerEx(self.handle.raw(), 0, Some(&mut position), FILE_CURRENT)? };
+        Ok(position as u64)
+    }
+
+    fn has_content_left_to_read(&self) -> io::Result<bool> {
+        Ok(self.current_file_offset()? < self.size)
+    }
+
+    fn from_handle(handle: IdentifierHandle) -> Result<Self, io::Error> {
+        let raw = handle.raw();
         // SAFETY: This function can be called with any valid handle.
         // https://docs.microsoft.com/en-us/windows/win32/api/fileapi/nf-fileapi-getfiletype
-        if GetFileType(handle) != FILE_TYPE_DISK {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Tried to convert handle to Identifier that was not a file handle.",
-            ));
-        }
-        let mut fi = MaybeUninit::<BY_HANDLE_FILE_INFORMATION>::uninit();
-        // SAFETY: This function is safe to call, if the handle is valid and a handle to a file.
-        // https://docs.microsoft.com/en-us/windows/win32/api/fileapi/nf-fileapi-getfileinformationbyhandle
-        GetFileInformationByHandle(handle, fi.as_mut_ptr())?;
+        let file_type = match unsafe { GetFileType(raw) } {
+            FILE_TYPE_DISK => FileType::File,
+            FILE_TYPE_PIPE => FileType::Pipe,
+            FILE_TYPE_CHAR => FileType::Char,
+            _ => FileType::Other,
+        };
 
-        // SAFETY: GetFileInformationByHandle returned successfully.
-        let fi = fi.assume_init();
+        // `GetFileInformationByHandle` only yields a meaningful volume serial and file index for
+        // on-disk files. Pipes, consoles and other non-disk handles are accepted, but they do not
+        // have a stable identity and never participate in the content-left-to-read check.
+        let (volume_serial, file_index, size) = if file_type == FileType::File {
+            let mut fi = MaybeUninit::<BY_HANDLE_FILE_INFORMATION>::uninit();
+            // SAFETY: This function is safe to call, if the handle is valid and a handle to a file.
+            // https://docs.microsoft.com/en-us/windows/win32/api/fileapi/nf-fileapi-getfileinformationbyhandle
+            unsafe { GetFileInformationByHandle(raw, fi.as_mut_ptr())? };
+
+            // SAFETY: GetFileInformationByHandle returned successfully.
+            let fi = unsafe { fi.assume_init() };
+
+            (
+                fi.dwVolumeSerialNumber,
+                u64::from(fi.nFileIndexHigh) << 32 | u64::from(fi.nFileIndexLow),
+                u64::from(fi.nFileSizeHigh) << 32 | u64::from(fi.nFileSizeLow),
+            )
+        } else {
+            // Non-disk handles (pipes, consoles, char devices, ...) have no meaningful volume
+            // serial or file index. Fold the raw handle value into `file_index` so that distinct
+            // handles keep distinct identities instead of all comparing equal and hashing alike —
+            // mirroring the Unix path, where each pipe or char device carries its own
+            // `st_dev`/`st_ino`. These handles never reach the content-left-to-read check, so the
+            // size is irrelevant.
+            (0, raw.0 as usize as u64, 0)
+        };
 
         Ok(Self {
-            volume_serial: fi.dwVolumeSerialNumber,
-            file_index: u64::from(fi.nFileIndexHigh) << 32 | u64::from(fi.nFileIndexLow),
+            volume_serial,
+            file_index,
+            size,
+            file_type,
             handle,
-            owns_handle,
         })
     }
+}
 
-    unsafe fn take_handle(&mut self) -> Option<HANDLE> {
-        if self.owns_handle {
-            self.owns_handle = false;
-            Some(mem::replace(&mut self.handle, INVALID_HANDLE_VALUE))
-        } else {
-            None
+impl Clircle for Identifier {
+    #[must_use]
+    fn into_inner(self) -> Option<File> {
+        match self.handle {
+            IdentifierHandle::Owned(handle) => Some(File::from(handle)),
+            IdentifierHandle::Borrowed(_) => None,
         }
     }
-}
 
-impl Clircle for Identifier {
+    fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    /// This method implements the conflict check that is used in the GNU coreutils program `cat`.
     #[must_use]
-    fn into_inner(mut self) -> Option<File> {
-        Some(unsafe { File::from_raw_handle(self.take_handle()?.0 as _) })
+    fn surely_conflicts_with(&self, other: &Self) -> bool {
+        PartialEq::eq(self, other)
+            && self.file_type == FileType::File
+            && other.has_content_left_to_read().unwrap_or(true)
     }
 }
 
@@ -89,24 +152,18 @@ impl TryFrom<Stdio> for Identifier {
             return Err(io::Error::last_os_error());
         }
 
-        unsafe { Self::try_from_raw_handle(handle, false) }
+        // SAFETY: The standard streams are valid for the entire lifetime of the program, so
+        // borrowing them for the lifetime of the identifier is sound.
+        let borrowed = unsafe { BorrowedHandle::borrow_raw(handle.0 as _) };
+        Self::from_handle(IdentifierHandle::Borrowed(borrowed))
     }
 }
+
 impl TryFrom<File> for Identifier {
     type Error = io::Error;
 
     fn try_from(file: File) -> Result<Self, Self::Error> {
-        unsafe { Self::try_from_raw_handle(HANDLE(file.into_raw_handle() as _), true) }
-    }
-}
-
-impl ops::Drop for Identifier {
-    fn drop(&mut self) {
-        unsafe {
-            if let Some(handle) = self.take_handle() {
-                let _ = CloseHandle(handle);
-            }
-        }
+        Self::from_handle(IdentifierHandle::Owned(OwnedHandle::from(file)))
     }
 }
 
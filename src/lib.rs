@@ -58,8 +58,10 @@ cfg_if::cfg_if! {
 
 #[cfg(feature = "serde")]
 use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fs::File;
+use std::hash::Hash;
 use std::io;
 
 /// The `Clircle` trait describes the public interface of the crate.
@@ -67,14 +69,20 @@ use std::io;
 /// Additionally, an implementation of `Eq` is required, that gives a simple way to check for
 /// conflicts, if using the more elaborate `surely_conflicts_with` method is not wanted.
 /// This trait is implemented for the structs `UnixIdentifier` and `WindowsIdentifier`.
-pub trait Clircle: Eq + TryFrom<Stdio> + TryFrom<File> {
+pub trait Clircle: Eq + Hash + TryFrom<Stdio> + TryFrom<File> {
     /// Returns the `File` that was used for `From<File>`. If the instance was created otherwise,
     /// this may also return `None`.
     fn into_inner(self) -> Option<File>;
 
+    /// Returns the kind of descriptor this identifier points at.
+    ///
+    /// Only [`FileType::File`] descriptors can ever `surely_conflicts_with` another identifier;
+    /// pipes, character devices and sockets are always safe to read and write concurrently.
+    fn file_type(&self) -> FileType;
+
     /// Checks whether the two values will without doubt conflict. By default, this always returns
-    /// `false`, but implementors can override this method. Currently, only the Unix implementation
-    /// overrides `surely_conflicts_with`.
+    /// `false`, but implementors can override this method. The Unix, WASI and Windows
+    /// implementations all override `surely_conflicts_with`.
     fn surely_conflicts_with(&self, _other: &Self) -> bool {
         false
     }
@@ -108,6 +116,26 @@ pub enum Stdio {
     Stderr,
 }
 
+/// The kind of descriptor an `Identifier` points at.
+///
+/// The variant is captured at construction from `GetFileType` on Windows and from the file mode
+/// on Unix and WASI. Only regular files (`File`) are subject to the read-write cycle check in
+/// `surely_conflicts_with`; the remaining kinds can always be read and written at the same time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum FileType {
+    /// A regular file on disk.
+    File,
+    /// A named pipe (FIFO) or anonymous pipe.
+    Pipe,
+    /// A character device, such as a terminal or a pty.
+    Char,
+    /// A socket.
+    Socket,
+    /// A descriptor of some other, unrecognized kind.
+    Other,
+}
+
 /// Finds a common `Identifier` in the two given slices.
 pub fn output_among_inputs<'o, T>(outputs: &'o [T], inputs: &[T]) -> Option<&'o T>
 where
@@ -124,6 +152,123 @@ where
     T::stdout().map_or(false, |stdout| inputs.contains(&stdout))
 }
 
+/// A data-flow graph over several input/output stages, used to detect whether the whole wiring of
+/// a pipeline forms a cycle.
+///
+/// Where [`output_among_inputs`] only does a single pairwise scan, a `ConflictGraph` accumulates
+/// many stages — each the inputs and outputs of one subprocess — and then looks for a cycle
+/// spanning all of them, e.g. process A writes a file that B reads and rewrites into a file that A
+/// reads back. Nodes are identified by the `Eq`/`Hash` identity that [`Clircle`] already provides,
+/// so two handles to the same file collapse into a single node. There is an edge from every input
+/// of a stage to every one of its outputs.
+pub struct ConflictGraph<'a, T>
+where
+    T: Clircle,
+{
+    nodes: Vec<&'a T>,
+    index_of: HashMap<&'a T, usize>,
+    edges: Vec<Vec<usize>>,
+}
+
+impl<'a, T> ConflictGraph<'a, T>
+where
+    T: Clircle,
+{
+    /// Creates an empty `ConflictGraph`.
+    #[must_use]
+    pub fn new() -> Self {
+        ConflictGraph {
+            nodes: Vec::new(),
+            index_of: HashMap::new(),
+            edges: Vec::new(),
+        }
+    }
+
+    /// Registers one stage of the pipeline, adding an edge from every input to every output.
+    pub fn add_stage(&mut self, inputs: &'a [T], outputs: &'a [T]) -> &mut Self {
+        let inputs: Vec<usize> = inputs.iter().map(|input| self.node(input)).collect();
+        let outputs: Vec<usize> = outputs.iter().map(|output| self.node(output)).collect();
+        for &input in &inputs {
+            for &output in &outputs {
+                if !self.edges[input].contains(&output) {
+                    self.edges[input].push(output);
+                }
+            }
+        }
+        self
+    }
+
+    /// Returns a cycle in the data flow as an ordered list of identifiers, or `None` if the graph
+    /// is acyclic.
+    ///
+    /// The returned `Vec` lists the nodes in the order they are traversed, so that the last node
+    /// has an edge back to the first.
+    #[must_use]
+    pub fn find_cycle(&self) -> Option<Vec<&'a T>> {
+        let mut color = vec![Color::White; self.nodes.len()];
+        let mut path = Vec::new();
+        for start in 0..self.nodes.len() {
+            if color[start] == Color::White {
+                if let Some(cycle) = self.visit(start, &mut color, &mut path) {
+                    return Some(cycle.into_iter().map(|node| self.nodes[node]).collect());
+                }
+            }
+        }
+        None
+    }
+
+    fn node(&mut self, ident: &'a T) -> usize {
+        if let Some(&index) = self.index_of.get(&ident) {
+            return index;
+        }
+        let index = self.nodes.len();
+        self.nodes.push(ident);
+        self.edges.push(Vec::new());
+        self.index_of.insert(ident, index);
+        index
+    }
+
+    /// Depth-first search that records the current path and reconstructs the cycle when it hits a
+    /// node that is still on the stack (a back edge).
+    fn visit(&self, node: usize, color: &mut [Color], path: &mut Vec<usize>) -> Option<Vec<usize>> {
+        color[node] = Color::Gray;
+        path.push(node);
+        for &next in &self.edges[node] {
+            match color[next] {
+                Color::Gray => {
+                    let start = path.iter().position(|&n| n == next)?;
+                    return Some(path[start..].to_vec());
+                }
+                Color::White => {
+                    if let Some(cycle) = self.visit(next, color, path) {
+                        return Some(cycle);
+                    }
+                }
+                Color::Black => {}
+            }
+        }
+        color[node] = Color::Black;
+        path.pop();
+        None
+    }
+}
+
+impl<'a, T> Default for ConflictGraph<'a, T>
+where
+    T: Clircle,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Color {
+    White,
+    Gray,
+    Black,
+}
+
 /// Identifies a file. The type forwards all methods to the platform implementation.
 #[derive(Debug, PartialEq, Eq, Hash)]
 pub struct Identifier(imp::Identifier);
@@ -134,6 +279,10 @@ impl Clircle for Identifier {
         self.0.into_inner()
     }
 
+    fn file_type(&self) -> FileType {
+        self.0.file_type()
+    }
+
     fn surely_conflicts_with(&self, other: &Self) -> bool {
         self.0.surely_conflicts_with(&other.0)
     }
@@ -155,6 +304,23 @@ impl TryFrom<File> for Identifier {
     }
 }
 
+#[cfg(unix)]
+impl Identifier {
+    /// Copies the still-unread tail of the input into an anonymous file (an in-memory `memfd` on
+    /// Linux, an unlinked temporary file elsewhere) and returns it, rewound to offset `0`.
+    ///
+    /// This lets a caller recover from a conflict reported by `surely_conflicts_with` (such as
+    /// `cat < x > x`) by reading from the snapshot instead of the original path, which is about to
+    /// be truncated or overwritten.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the anonymous file cannot be created or if copying the remaining bytes fails.
+    pub fn snapshot_remaining(&self) -> io::Result<File> {
+        self.0.snapshot_remaining()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -194,4 +360,58 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_conflict_graph_detects_multi_stage_cycle() -> Result<(), &'static str> {
+        let dir = tempfile::tempdir().expect("Couldn't create tempdir.");
+        let x = dir.path().join("x");
+        let y = dir.path().join("y");
+        let open = |path| {
+            Identifier::try_from(File::create(path).expect("Couldn't create file."))
+                .expect("Couldn't create identifier.")
+        };
+
+        // Stage 1 reads x and writes y, stage 2 reads y and writes x: x -> y -> x.
+        let stage1_inputs = [open(&x)];
+        let stage1_outputs = [open(&y)];
+        let stage2_inputs = [open(&y)];
+        let stage2_outputs = [open(&x)];
+
+        let mut graph = ConflictGraph::new();
+        graph.add_stage(&stage1_inputs, &stage1_outputs);
+        graph.add_stage(&stage2_inputs, &stage2_outputs);
+
+        let cycle = graph.find_cycle().ok_or("Expected a cycle, found none.")?;
+        if cycle.len() != 2 {
+            return Err("Cycle should contain exactly the two distinct files.");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflict_graph_acyclic() -> Result<(), &'static str> {
+        let dir = tempfile::tempdir().expect("Couldn't create tempdir.");
+        let x = dir.path().join("x");
+        let y = dir.path().join("y");
+        let z = dir.path().join("z");
+        let open = |path| {
+            Identifier::try_from(File::create(path).expect("Couldn't create file."))
+                .expect("Couldn't create identifier.")
+        };
+
+        // x -> y and y -> z, which does not close a loop.
+        let stage1_inputs = [open(&x)];
+        let stage1_outputs = [open(&y)];
+        let stage2_inputs = [open(&y)];
+        let stage2_outputs = [open(&z)];
+
+        let mut graph = ConflictGraph::new();
+        graph.add_stage(&stage1_inputs, &stage1_outputs);
+        graph.add_stage(&stage2_inputs, &stage2_outputs);
+
+        if graph.find_cycle().is_some() {
+            return Err("Detected a cycle in an acyclic graph.");
+        }
+        Ok(())
+    }
 }